@@ -0,0 +1,214 @@
+use futures::channel::mpsc;
+use futures::stream::Stream;
+use std::ptr;
+use widestring::WideCString;
+use winapi::shared::ntdef::HRESULT;
+use winapi::shared::winerror::{E_INVALIDARG, FAILED};
+use winapi::shared::wtypes::BSTR;
+use winapi::um::oleauto::{SysAllocString, SysFreeString};
+use winapi::um::wbemcli::{IWbemObjectSink, WBEM_FLAG_BIDIRECTIONAL};
+use wio::com::ComPtr;
+
+use crate::query_sink::{CancelableQueryStream, QuerySink, SinkSecurity};
+use crate::result_enumerator::IWbemClassWrapper;
+use crate::WMIConnection;
+use crate::WMIError;
+
+/// Owns a `BSTR` allocated with `SysAllocString` and frees it on drop.
+struct BStrGuard(BSTR);
+
+impl BStrGuard {
+    /// Fails with [`WMIError::HResultError`] (rather than panicking) when `s`
+    /// contains an embedded NUL, since `query` is caller-supplied input reachable
+    /// from the public API.
+    fn from_str(s: impl AsRef<str>) -> Result<Self, WMIError> {
+        let wide = WideCString::from_str(s.as_ref())
+            .map_err(|_| WMIError::HResultError { hres: E_INVALIDARG })?;
+        Ok(Self(unsafe { SysAllocString(wide.as_ptr()) }))
+    }
+
+    fn as_bstr(&self) -> BSTR {
+        self.0
+    }
+}
+
+impl Drop for BStrGuard {
+    fn drop(&mut self) {
+        unsafe { SysFreeString(self.0) };
+    }
+}
+
+impl WMIConnection {
+    /// Executes a one-shot WQL query asynchronously via `IWbemServices::ExecQueryAsync`
+    /// and returns a [`Stream`] that yields the finite result set, terminated once
+    /// `SetStatus` reports `WBEM_STATUS_COMPLETE`.
+    pub fn exec_query_async(
+        &self,
+        query: impl AsRef<str>,
+    ) -> Result<impl Stream<Item = Result<IWbemClassWrapper, WMIError>>, WMIError> {
+        self.exec_query_async_with_security(query, SinkSecurity::default())
+    }
+
+    /// Same as [`exec_query_async`], but lets the caller override the
+    /// impersonation/authentication level applied to the sink's proxy.
+    ///
+    /// [`exec_query_async`]: Self::exec_query_async
+    pub fn exec_query_async_with_security(
+        &self,
+        query: impl AsRef<str>,
+        security: SinkSecurity,
+    ) -> Result<impl Stream<Item = Result<IWbemClassWrapper, WMIError>>, WMIError> {
+        let (tx, rx) = mpsc::unbounded();
+
+        let p_sink = QuerySink::new(tx, security)?;
+        self.register_query_sink(query, &p_sink)?;
+
+        Ok(CancelableQueryStream::new(self.svc.clone(), p_sink, rx))
+    }
+
+    /// Same as [`exec_query_async`], but caps the number of in-flight results
+    /// buffered for a slow consumer at `capacity` instead of growing without limit —
+    /// the scenario this request was meant to cover: a fast WMI provider feeding a
+    /// slow consumer over a large result set, per [`QuerySink::with_capacity`].
+    ///
+    /// [`exec_query_async`]: Self::exec_query_async
+    pub fn exec_query_async_with_capacity(
+        &self,
+        query: impl AsRef<str>,
+        capacity: usize,
+        security: SinkSecurity,
+    ) -> Result<impl Stream<Item = Result<IWbemClassWrapper, WMIError>>, WMIError> {
+        let (p_sink, rx) = QuerySink::with_capacity(capacity, security)?;
+        self.register_query_sink(query, &p_sink)?;
+
+        Ok(CancelableQueryStream::new(self.svc.clone(), p_sink, rx))
+    }
+
+    fn register_query_sink(
+        &self,
+        query: impl AsRef<str>,
+        p_sink: &ComPtr<IWbemObjectSink>,
+    ) -> Result<(), WMIError> {
+        let query_language = BStrGuard::from_str("WQL")?;
+        let query = BStrGuard::from_str(query)?;
+
+        let hres: HRESULT = unsafe {
+            self.svc.ExecQueryAsync(
+                query_language.as_bstr(),
+                query.as_bstr(),
+                WBEM_FLAG_BIDIRECTIONAL as i32,
+                ptr::null_mut(),
+                p_sink.as_raw() as *mut _,
+            )
+        };
+
+        if FAILED(hres) {
+            return Err(WMIError::HResultError { hres });
+        }
+
+        Ok(())
+    }
+
+    /// Subscribes to a WMI event notification query (e.g. `__InstanceCreationEvent`,
+    /// `__InstanceModificationEvent`) via `IWbemServices::ExecNotificationQueryAsync`
+    /// and returns a [`Stream`] of incoming events.
+    ///
+    /// Unlike [`exec_query_async`], which delivers a finite result set terminated by
+    /// `WBEM_STATUS_COMPLETE`, the returned stream is long-lived: it keeps yielding
+    /// events for as long as the subscription is active, and only ends when WMI
+    /// reports a terminal status or the stream itself is dropped.
+    ///
+    /// [`exec_query_async`]: Self::exec_query_async
+    pub fn exec_notification_query_async(
+        &self,
+        query: impl AsRef<str>,
+    ) -> Result<impl Stream<Item = Result<IWbemClassWrapper, WMIError>>, WMIError> {
+        self.exec_notification_query_async_with_security(query, SinkSecurity::default())
+    }
+
+    /// Same as [`exec_notification_query_async`], but lets the caller override the
+    /// impersonation/authentication level applied to the sink's proxy, for
+    /// subscriptions against a protected namespace or a remote host.
+    ///
+    /// [`exec_notification_query_async`]: Self::exec_notification_query_async
+    pub fn exec_notification_query_async_with_security(
+        &self,
+        query: impl AsRef<str>,
+        security: SinkSecurity,
+    ) -> Result<impl Stream<Item = Result<IWbemClassWrapper, WMIError>>, WMIError> {
+        let (tx, rx) = mpsc::unbounded();
+
+        let p_sink = QuerySink::new(tx, security)?;
+        self.register_notification_sink(query, &p_sink)?;
+
+        Ok(CancelableQueryStream::new(self.svc.clone(), p_sink, rx))
+    }
+
+    /// Same as [`exec_notification_query_async`], but caps the number of in-flight
+    /// events buffered for a slow consumer at `capacity` instead of growing without
+    /// limit, per [`QuerySink::with_capacity`].
+    ///
+    /// [`exec_notification_query_async`]: Self::exec_notification_query_async
+    pub fn exec_notification_query_async_with_capacity(
+        &self,
+        query: impl AsRef<str>,
+        capacity: usize,
+        security: SinkSecurity,
+    ) -> Result<impl Stream<Item = Result<IWbemClassWrapper, WMIError>>, WMIError> {
+        let (p_sink, rx) = QuerySink::with_capacity(capacity, security)?;
+        self.register_notification_sink(query, &p_sink)?;
+
+        Ok(CancelableQueryStream::new(self.svc.clone(), p_sink, rx))
+    }
+
+    fn register_notification_sink(
+        &self,
+        query: impl AsRef<str>,
+        p_sink: &ComPtr<IWbemObjectSink>,
+    ) -> Result<(), WMIError> {
+        let query_language = BStrGuard::from_str("WQL")?;
+        let query = BStrGuard::from_str(query)?;
+
+        let hres: HRESULT = unsafe {
+            self.svc.ExecNotificationQueryAsync(
+                query_language.as_bstr(),
+                query.as_bstr(),
+                WBEM_FLAG_BIDIRECTIONAL as i32,
+                ptr::null_mut(),
+                p_sink.as_raw() as *mut _,
+            )
+        };
+
+        if FAILED(hres) {
+            return Err(WMIError::HResultError { hres });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::fixtures::*;
+
+    #[test]
+    fn it_should_build_a_notification_query_stream() {
+        let con = wmi_con();
+
+        let stream = con.exec_notification_query_async(
+            "SELECT * FROM __InstanceModificationEvent WITHIN 1 WHERE TargetInstance ISA 'Win32_Process'",
+        );
+
+        assert!(stream.is_ok());
+    }
+
+    #[test]
+    fn it_should_reject_a_query_with_an_embedded_nul() {
+        let con = wmi_con();
+
+        let result = con.exec_notification_query_async("SELECT * FROM Win32_Process\0WHERE Foo");
+
+        assert!(matches!(result, Err(WMIError::HResultError { .. })));
+    }
+}
@@ -0,0 +1,15 @@
+use winapi::shared::ntdef::HRESULT;
+
+/// Errors surfaced by synchronous and asynchronous WMI operations.
+#[derive(Debug)]
+pub enum WMIError {
+    /// A WMI call returned a failure `HRESULT`.
+    HResultError { hres: HRESULT },
+    /// An asynchronous operation completed (`SetStatus` with `WBEM_STATUS_COMPLETE`)
+    /// with a failure `HRESULT`, optionally carrying the WMI-provided extended error
+    /// message.
+    AsyncOperationError {
+        hres: HRESULT,
+        message: Option<String>,
+    },
+}
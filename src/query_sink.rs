@@ -1,23 +1,35 @@
 use winapi::{
-    um::wbemcli::{
-        {IWbemClassObject,IWbemObjectSink, IWbemObjectSinkVtbl},
-        WBEM_NO_ERROR,
-        WBEM_STATUS_COMPLETE,
+    um::{
+        wbemcli::{
+            {IWbemClassObject,IWbemObjectSink, IWbemObjectSinkVtbl, IWbemServices},
+            WBEM_NO_ERROR,
+            WBEM_STATUS_COMPLETE,
+        },
+        oleauto::SysStringLen,
+        combaseapi::CoSetProxyBlanket,
+        objidl::EOAC_NONE,
     },
     shared::{
         ntdef::HRESULT,
         wtypes::BSTR,
-        winerror::E_POINTER,
+        winerror::{E_POINTER, FAILED},
     },
     ctypes::{
         c_long,
     },
 };
+use winapi::um::wbemcli::{WBEM_E_ACCESS_DENIED, WBEM_E_OUT_OF_MEMORY};
 use com_impl::{ComImpl, VTable, Refcount};
 use log::{trace, warn};
+use std::pin::Pin;
+use std::ptr;
 use std::ptr::NonNull;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
 use wio::com::ComPtr;
-use futures::channel::mpsc::UnboundedSender;
+use winapi::um::rpcdce::{RPC_C_AUTHN_LEVEL_CALL, RPC_C_AUTHN_WINNT, RPC_C_AUTHZ_NONE, RPC_C_IMP_LEVEL_IMPERSONATE};
+use futures::channel::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use futures::stream::Stream;
 use crate::result_enumerator::IWbemClassWrapper;
 use crate::WMIError;
 
@@ -25,6 +37,10 @@ use crate::WMIError;
 /// This [Sink] receives asynchronously the result of the query,
 /// through Indicate calls. When finished,the SetStatus method
 /// is called.
+/// The same sink backs both `ExecQueryAsync`, where `SetStatus` is guaranteed to be
+/// called exactly once to signal completion of a finite result set, and
+/// `ExecNotificationQueryAsync`, where `Indicate` may keep firing indefinitely and
+/// `SetStatus` is only expected on a genuine completion/error or on teardown.
 /// [Sink]: https://en.wikipedia.org/wiki/Sink_(computing)
 /// # https://docs.microsoft.com/fr-fr/windows/win32/wmisdk/example--getting-wmi-data-from-the-local-computer-asynchronously
 #[repr(C)]
@@ -33,18 +49,129 @@ use crate::WMIError;
 pub struct QuerySink {
     vtbl: VTable<IWbemObjectSinkVtbl>,
     refcount: Refcount,
-    sender: UnboundedSender<Result<IWbemClassWrapper, WMIError>>,
+    channel: SinkChannel,
+}
+
+/// The channel backing a [`QuerySink`]. `Unbounded` keeps growing to absorb
+/// whatever `Indicate` delivers; `Bounded` caps the number of in-flight
+/// `IWbemClassWrapper`s so a fast provider feeding a slow consumer can't pile up
+/// unbounded COM references.
+///
+/// `Bounded` wraps its `Sender` in a `Mutex` rather than cloning it per send: each
+/// clone of a bounded `Sender` gets its own guaranteed slot on top of the channel's
+/// capacity, so sending through a fresh clone every time defeats the bound entirely.
+/// Locking the single shared `Sender` keeps `capacity` meaning what it says.
+enum SinkChannel {
+    Unbounded(UnboundedSender<Result<IWbemClassWrapper, WMIError>>),
+    Bounded(Mutex<Sender<Result<IWbemClassWrapper, WMIError>>>),
+}
+
+impl SinkChannel {
+    fn close(&self) {
+        match self {
+            SinkChannel::Unbounded(tx) => tx.close_channel(),
+            SinkChannel::Bounded(tx) => tx.lock().unwrap().close_channel(),
+        }
+    }
+
+    fn send_err(&self, err: WMIError) {
+        let res = match self {
+            SinkChannel::Unbounded(tx) => tx.unbounded_send(Err(err)).map_err(|e| e.to_string()),
+            SinkChannel::Bounded(tx) => tx
+                .lock()
+                .unwrap()
+                .try_send(Err(err))
+                .map_err(|e| e.to_string()),
+        };
+        if let Err(e) = res {
+            warn!("Error while sending error to receiver: {}", e);
+        }
+    }
 }
 
 impl QuerySink {
-    /// Creates a QuerySink with RefCount = 1
+    /// Creates a QuerySink with RefCount = 1, then applies `security` to the sink's
+    /// proxy via `CoSetProxyBlanket` so that `Indicate`/`SetStatus` callbacks from an
+    /// out-of-process or remote WMI host run with the expected impersonation and
+    /// authentication level.
     /// ref count is handled by Com Impl in create_raw
     ///
-    pub fn new(sender: UnboundedSender<Result<IWbemClassWrapper, WMIError>>) -> ComPtr<IWbemObjectSink> {
-        let ptr = QuerySink::create_raw(sender);
+    pub fn new(
+        sender: UnboundedSender<Result<IWbemClassWrapper, WMIError>>,
+        security: SinkSecurity,
+    ) -> Result<ComPtr<IWbemObjectSink>, WMIError> {
+        Self::create(SinkChannel::Unbounded(sender), security)
+    }
+
+    /// Creates a QuerySink backed by a bounded channel of the given `capacity`,
+    /// returning the matching [`Receiver`] alongside the sink. Once `capacity`
+    /// in-flight objects are unacknowledged by the consumer, `Indicate` stops
+    /// accepting further objects and reports `WBEM_E_OUT_OF_MEMORY` to WMI instead of
+    /// growing the queue without limit. A `capacity` of 0 behaves like 1.
+    pub fn with_capacity(
+        capacity: usize,
+        security: SinkSecurity,
+    ) -> Result<(ComPtr<IWbemObjectSink>, Receiver<Result<IWbemClassWrapper, WMIError>>), WMIError> {
+        // A bounded channel holds `buffer` messages plus one slot per sender; the
+        // single sender's slot is counted in `capacity`.
+        let (tx, rx) = mpsc::channel(capacity.saturating_sub(1));
+        let p_sink = Self::create(SinkChannel::Bounded(Mutex::new(tx)), security)?;
+        Ok((p_sink, rx))
+    }
+
+    fn create(channel: SinkChannel, security: SinkSecurity) -> Result<ComPtr<IWbemObjectSink>, WMIError> {
+        let ptr = QuerySink::create_raw(channel);
         let ptr = ptr as *mut IWbemObjectSink;
         // ComPtr does not call AddRef
-        unsafe { ComPtr::from_raw(ptr) }
+        let p_sink = unsafe { ComPtr::from_raw(ptr) };
+
+        security.apply_to(&p_sink)?;
+
+        Ok(p_sink)
+    }
+}
+
+/// Impersonation and authentication level to apply to an async sink's proxy via
+/// `CoSetProxyBlanket`, so that callbacks into the sink from a protected namespace or
+/// a remote host run under the expected identity instead of being refused by the
+/// "unsecured apartment" check.
+#[derive(Debug, Clone, Copy)]
+pub struct SinkSecurity {
+    pub impersonation_level: u32,
+    pub authentication_level: u32,
+}
+
+impl Default for SinkSecurity {
+    /// Matches the defaults `CoInitializeSecurity` otherwise leaves in place:
+    /// impersonate the caller, authenticate at the call level.
+    fn default() -> Self {
+        Self {
+            impersonation_level: RPC_C_IMP_LEVEL_IMPERSONATE,
+            authentication_level: RPC_C_AUTHN_LEVEL_CALL,
+        }
+    }
+}
+
+impl SinkSecurity {
+    fn apply_to(&self, p_sink: &ComPtr<IWbemObjectSink>) -> Result<(), WMIError> {
+        let hres = unsafe {
+            CoSetProxyBlanket(
+                p_sink.as_raw() as *mut _,
+                RPC_C_AUTHN_WINNT,
+                RPC_C_AUTHZ_NONE,
+                ptr::null_mut(),
+                self.authentication_level,
+                self.impersonation_level,
+                ptr::null_mut(),
+                EOAC_NONE,
+            )
+        };
+
+        if FAILED(hres) {
+            return Err(WMIError::HResultError { hres });
+        }
+
+        Ok(())
     }
 }
 
@@ -62,7 +189,6 @@ unsafe impl IWbemObjectSink for QuerySink {
         }
 
         let lObjectCount = lObjectCount as usize;
-        let tx = self.sender.clone();
 
         unsafe {
             // The array memory of apObjArray is read-only, and is owned by the caller of the Indicate method.
@@ -73,17 +199,29 @@ unsafe impl IWbemObjectSink for QuerySink {
                 let p_el = *apObjArray.offset(i as isize);
                 // check for null pointer before cloning
                 if p_el.is_null() {
-                    // TODO: check how Indicate error code are handled by WMI
-                    // TODO: inform receiver with tx.try_send(Err(...))
                     // See https://docs.microsoft.com/en-us/windows/win32/learnwin32/error-handling-in-com
+                    warn!("Indicate called with a null object, informing receiver");
+                    self.channel.send_err(WMIError::HResultError { hres: E_POINTER });
                     return E_POINTER;
                 }
                 // extend ClassObject lifespan beyond scope of Indicate method
                 let wbemClassObject = IWbemClassWrapper::clone(NonNull::new(p_el));
                 // send the result to the receiver
-                if let Err(e) = tx.unbounded_send(Ok(wbemClassObject)) {
-                    // TODO: send error back to WMI
-                    warn!("Error while sending object: {}", e);
+                match &self.channel {
+                    SinkChannel::Unbounded(tx) => {
+                        if let Err(e) = tx.unbounded_send(Ok(wbemClassObject)) {
+                            warn!("Error while sending object: {}", e);
+                        }
+                    }
+                    SinkChannel::Bounded(tx) => {
+                        if let Err(e) = tx.lock().unwrap().try_send(Ok(wbemClassObject)) {
+                            // The consumer isn't keeping up (or has dropped the
+                            // receiver): refuse further objects instead of buffering
+                            // them without limit.
+                            warn!("Sink at capacity, rejecting object: {}", e);
+                            return WBEM_E_OUT_OF_MEMORY as i32;
+                        }
+                    }
                 }
             }
         }
@@ -94,8 +232,8 @@ unsafe impl IWbemObjectSink for QuerySink {
     pub unsafe fn set_status(
         &self,
         lFlags: c_long,
-        _hResult: HRESULT,
-        _strParam: BSTR,
+        hResult: HRESULT,
+        strParam: BSTR,
         _pObjParam: *mut IWbemClassObject
     ) -> HRESULT {
         // SetStatus is called only once as flag=WBEM_FLAG_BIDIRECTIONAL in ExecQueryAsync
@@ -104,13 +242,109 @@ unsafe impl IWbemObjectSink for QuerySink {
         // you are guaranteed to receive one and only one call to SetStatus
 
         if lFlags == WBEM_STATUS_COMPLETE as i32 {
+            if FAILED(hResult) {
+                let message = if strParam.is_null() {
+                    None
+                } else {
+                    Some(unsafe { bstr_to_string(strParam) })
+                };
+                warn!("Async call completed with failure {:#x}: {:?}", hResult, message);
+                self.channel.send_err(WMIError::AsyncOperationError {
+                    hres: hResult,
+                    message,
+                });
+            }
             trace!("End of async result, closing transmitter");
-            self.sender.close_channel();
+            self.channel.close();
         }
         WBEM_NO_ERROR as i32
     }
 }
 
+/// Reads a `BSTR`'s length-prefixed UTF-16 buffer into an owned `String`.
+/// `bstr` must be a valid, non-null `BSTR` for the duration of the call.
+unsafe fn bstr_to_string(bstr: BSTR) -> String {
+    let len = SysStringLen(bstr) as usize;
+    let slice = std::slice::from_raw_parts(bstr, len);
+    String::from_utf16_lossy(slice)
+}
+
+/// A [`Stream`] of query/notification results backed by a [`QuerySink`], which
+/// cancels the outstanding asynchronous WMI call when dropped.
+///
+/// Dropping the receiving end of the channel used to leave `Indicate` firing into a
+/// sink nobody was listening to anymore, keeping the subscription (and any
+/// cross-apartment marshaling it requires) alive for no reason. This type keeps the
+/// originating `IWbemServices` pointer alongside the sink's `ComPtr`, and on `Drop`
+/// calls `IWbemServices::CancelAsyncCall` to unregister the sink before releasing it,
+/// mirroring the `CancelAsyncCall` contract that releases the sink's reference.
+pub struct CancelableQueryStream {
+    svc: ComPtr<IWbemServices>,
+    p_sink: ComPtr<IWbemObjectSink>,
+    rx: ResultReceiver,
+}
+
+impl CancelableQueryStream {
+    pub(crate) fn new(
+        svc: ComPtr<IWbemServices>,
+        p_sink: ComPtr<IWbemObjectSink>,
+        rx: impl Into<ResultReceiver>,
+    ) -> Self {
+        Self {
+            svc,
+            p_sink,
+            rx: rx.into(),
+        }
+    }
+}
+
+impl Stream for CancelableQueryStream {
+    type Item = Result<IWbemClassWrapper, WMIError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+/// The receiving half of a [`QuerySink`]/[`QuerySink::with_capacity`] channel, so
+/// [`CancelableQueryStream`] can wrap either the unbounded or the bounded variant.
+pub enum ResultReceiver {
+    Unbounded(UnboundedReceiver<Result<IWbemClassWrapper, WMIError>>),
+    Bounded(Receiver<Result<IWbemClassWrapper, WMIError>>),
+}
+
+impl From<UnboundedReceiver<Result<IWbemClassWrapper, WMIError>>> for ResultReceiver {
+    fn from(rx: UnboundedReceiver<Result<IWbemClassWrapper, WMIError>>) -> Self {
+        ResultReceiver::Unbounded(rx)
+    }
+}
+
+impl From<Receiver<Result<IWbemClassWrapper, WMIError>>> for ResultReceiver {
+    fn from(rx: Receiver<Result<IWbemClassWrapper, WMIError>>) -> Self {
+        ResultReceiver::Bounded(rx)
+    }
+}
+
+impl Stream for ResultReceiver {
+    type Item = Result<IWbemClassWrapper, WMIError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            ResultReceiver::Unbounded(rx) => Pin::new(rx).poll_next(cx),
+            ResultReceiver::Bounded(rx) => Pin::new(rx).poll_next(cx),
+        }
+    }
+}
+
+impl Drop for CancelableQueryStream {
+    fn drop(&mut self) {
+        trace!("Stream dropped, cancelling outstanding async call");
+        unsafe {
+            self.svc.CancelAsyncCall(self.p_sink.as_raw());
+        }
+    }
+}
+
 
 #[allow(non_snake_case)]
 #[allow(non_camel_case_types)]
@@ -120,11 +354,43 @@ mod tests {
     use crate::tests::fixtures::*;
     use futures::channel::mpsc;
 
+    #[test]
+    fn it_should_cancel_async_call_when_stream_is_dropped() {
+        let con = wmi_con();
+
+        let stream = con
+            .exec_query_async(r#"SELECT * FROM Win32_OperatingSystem"#)
+            .unwrap();
+
+        // Dropping the stream before the finite result set completes must not panic:
+        // `CancelableQueryStream::drop` calls `IWbemServices::CancelAsyncCall` to tear
+        // down the outstanding subscription instead of leaving `Indicate` firing into
+        // a channel nobody is listening to anymore.
+        drop(stream);
+    }
+
+    #[test]
+    fn it_should_apply_custom_sink_security() {
+        use winapi::um::rpcdce::{RPC_C_AUTHN_LEVEL_PKT, RPC_C_IMP_LEVEL_IDENTIFY};
+
+        let (tx, _rx) = mpsc::unbounded::<Result<IWbemClassWrapper, WMIError>>();
+        let security = SinkSecurity {
+            impersonation_level: RPC_C_IMP_LEVEL_IDENTIFY,
+            authentication_level: RPC_C_AUTHN_LEVEL_PKT,
+        };
+
+        // QuerySink::new applies `security` to the sink's proxy via
+        // CoSetProxyBlanket; a non-default level must still succeed.
+        let p_sink = QuerySink::new(tx, security);
+
+        assert!(p_sink.is_ok());
+    }
+
     #[test]
     fn it_should_use_async_channel_to_send_result() {
         let con = wmi_con();
         let (tx, mut rx) = mpsc::unbounded::<Result<IWbemClassWrapper, WMIError>>();
-        let p_sink: ComPtr<IWbemObjectSink> = QuerySink::new(tx);
+        let p_sink: ComPtr<IWbemObjectSink> = QuerySink::new(tx, SinkSecurity::default()).unwrap();
 
         let raw_os = con.get_raw_by_path(r#"\\.\root\cimv2:Win32_OperatingSystem=@"#).unwrap();
         let raw_os2 = con.get_raw_by_path(r#"\\.\root\cimv2:Win32_OperatingSystem=@"#).unwrap();
@@ -164,4 +430,54 @@ mod tests {
             assert!(false);
         }
     }
+
+    #[test]
+    fn it_should_send_failure_through_channel_on_failing_set_status() {
+        let (tx, mut rx) = mpsc::unbounded::<Result<IWbemClassWrapper, WMIError>>();
+        let p_sink: ComPtr<IWbemObjectSink> = QuerySink::new(tx, SinkSecurity::default()).unwrap();
+
+        let hres = unsafe {
+            p_sink.SetStatus(
+                WBEM_STATUS_COMPLETE as i32,
+                WBEM_E_ACCESS_DENIED as i32,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        assert_eq!(hres, WBEM_NO_ERROR as i32);
+
+        if let Some(Err(WMIError::AsyncOperationError { hres, message })) = rx.try_next().unwrap() {
+            assert_eq!(hres, WBEM_E_ACCESS_DENIED as i32);
+            assert_eq!(message, None);
+        } else {
+            assert!(false);
+        }
+    }
+
+    #[test]
+    fn it_should_reject_objects_once_bounded_capacity_is_reached() {
+        let con = wmi_con();
+        let (p_sink, mut rx) = QuerySink::with_capacity(1, SinkSecurity::default()).unwrap();
+
+        let raw_os = con.get_raw_by_path(r#"\\.\root\cimv2:Win32_OperatingSystem=@"#).unwrap();
+        let raw_os2 = con.get_raw_by_path(r#"\\.\root\cimv2:Win32_OperatingSystem=@"#).unwrap();
+        let ptr: *mut IWbemClassObject = raw_os.inner.unwrap().as_ptr();
+        let ptr2: *mut IWbemClassObject = raw_os2.inner.unwrap().as_ptr();
+
+        // Nobody drains `rx`, so the single slot fills on the first object and the
+        // second `Indicate` call must be refused instead of buffering forever.
+        let mut first_arr = vec![ptr];
+        let hres = unsafe { p_sink.Indicate(first_arr.len() as i32, first_arr.as_mut_ptr()) };
+        assert_eq!(hres, WBEM_NO_ERROR as i32);
+
+        let mut second_arr = vec![ptr2];
+        let hres = unsafe { p_sink.Indicate(second_arr.len() as i32, second_arr.as_mut_ptr()) };
+        assert_eq!(hres, WBEM_E_OUT_OF_MEMORY as i32);
+
+        if let Some(Ok(first)) = rx.try_next().unwrap() {
+            assert_eq!(first.class().unwrap().as_str(), "Win32_OperatingSystem");
+        } else {
+            assert!(false);
+        }
+    }
 }